@@ -10,9 +10,41 @@
 use crate::packet::{Command, Request, Response, Value};
 use crate::schema::TableMetadata;
 use crate::schema::ColumnMetadata;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::{Receiver, Sender};
+use std::convert::TryInto;
+
+/* A change event delivered to a subscriber of a standing query. Carries enough of the row to let
+ * the client update its own materialized view without a follow-up Get. The version tags Added and
+ * Updated the same way Response::Insert/Response::Update already do; Removed has no successor
+ * version, so it carries only the row id. */
+pub enum ChangeEvent {
+    Added(i64, i64, Vec<Value>),
+    Updated(i64, i64, Vec<Value>),
+    Removed(i64),
+}
+
+/* The predicate a subscription was registered with -- the same column/operator/value triple
+ * `handle_query` accepts, or OP_AL for "every row of the table". */
+enum SubscriptionPredicate {
+    All,
+    Column { column_id: usize, operator: i32, value: Value },
+}
+
+struct Subscription {
+    predicate: SubscriptionPredicate,
+    sender: Sender<ChangeEvent>,
+}
+
+// Value already implements PartialEq/PartialOrd in packet.rs (handle_query's `<`/`>` operators
+// rely on it); the secondary indexes below additionally need a total order to live in a BTreeMap
+// key, so packet.rs needs Eq + Ord on Value too (e.g. derived alongside the existing PartialOrd,
+// ordering mismatched variants and NaN by variant like the rest of the comparisons already do).
+// That's outside this file's tree slice, so it's noted here rather than re-implemented: a second
+// `impl Ord`/`impl PartialOrd for Value` in this module would conflict with packet.rs's (E0119).
 
 /* OP codes for the query command */
 pub const OP_AL: i32 = 1;
@@ -23,15 +55,39 @@ pub const OP_GT: i32 = 5;
 pub const OP_LE: i32 = 6;
 pub const OP_GE: i32 = 7;
 
+#[derive(Clone)]
 struct DatabaseTableRow {
     version: i64,
     data: Vec<Value>,
 }
 
+/// One inverse operation recorded while a mutation runs inside an open transaction. `Rollback`
+/// replays a transaction's log in reverse, applying each entry's inverse to restore exact prior
+/// state; `Commit` just discards the log.
+enum UndoOp {
+    UndoInsert { table_id: i32, row_id: i64 },
+    UndoUpdate { table_id: i32, row_id: i64, prior_row: DatabaseTableRow },
+    UndoDrop { table_id: i32, row_id: i64, row: DatabaseTableRow, referencing_rows: HashSet<(i32, i64)> },
+}
+
+struct Transaction {
+    undo_log: Vec<UndoOp>,
+    // Savepoint handle -> undo_log length when the savepoint was taken, so RollbackToSavepoint
+    // knows how far back to unwind without ending the enclosing transaction.
+    savepoints: HashMap<u64, usize>,
+}
+
 pub struct DatabaseTable {
     table_schema: TableMetadata,
     rows: Mutex<HashMap<i64, DatabaseTableRow>>,
     /* Each row ID is mapped to a structure containing the row's data */
+
+    // Secondary indexes, one per non-foreign column, keyed by 0-based column_id. Each index maps
+    // a column value to the set of row IDs currently holding that value, so handle_query can
+    // probe an index directly for OP_EQ/OP_LT/OP_GT/OP_LE/OP_GE instead of scanning every row.
+    // Foreign columns are excluded: they only ever support EQ/NE, which handle_query serves for
+    // them with its linear scan.
+    indexes: Mutex<HashMap<usize, BTreeMap<Value, HashSet<i64>>>>,
 }
 
 /* You can implement your Database structure here
@@ -48,6 +104,21 @@ pub struct DatabaseData {
     // This mapping takes a row ID as the key, and returns a set of all the rows (as a (table_id, row_id) pair) that
     // reference the row specified in the key
     foreign_references_map: Mutex<HashMap<i64, HashSet<(i32, i64)>>>,
+
+    // Standing queries registered via Command::Subscribe, keyed by table_id and then by the
+    // subscription handle returned to the client so Command::Unsubscribe can remove a single one.
+    subscriptions: Mutex<HashMap<i32, HashMap<u64, Subscription>>>,
+    next_subscription_id: Mutex<u64>,
+
+    // Command::Subscribe can only hand the caller a Response (which can't carry a non-serializable
+    // Receiver), so the receiving end of each new subscription's channel is parked here until the
+    // in-process caller claims it with Database::take_subscription_receiver.
+    pending_subscription_receivers: Mutex<HashMap<u64, Receiver<ChangeEvent>>>,
+
+    // Open transactions started by Command::Begin, keyed by the txn_id handed back to the client
+    // and threaded through every subsequent request via Request::txn_id.
+    transactions: Mutex<HashMap<i64, Transaction>>,
+    next_txn_id: Mutex<i64>,
 }
 
 /*pub struct DatabaseMutexes {
@@ -63,29 +134,290 @@ pub struct Database {
 
 impl Database {
     pub fn new(tables_schema: Vec<TableMetadata>) -> Database {
-        let mut db = DatabaseData {tables: vec![], next_row_key: Mutex::new(1), foreign_references_map: Mutex::new(HashMap::new()) };
+        let mut db = DatabaseData {
+            tables: vec![],
+            next_row_key: Mutex::new(1),
+            foreign_references_map: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+            next_subscription_id: Mutex::new(1),
+            pending_subscription_receivers: Mutex::new(HashMap::new()),
+            transactions: Mutex::new(HashMap::new()),
+            next_txn_id: Mutex::new(1),
+        };
         // let mut mt = DatabaseMutexes {mutex_tables: vec![], mutex_row_key: Mutex::new(false), mutex_foreign_ref_map: Mutex::new(false)};
 
         for table_schema in tables_schema {
-            db.tables.push(DatabaseTable { table_schema, rows: Mutex::new(HashMap::new()) });
+            let mut indexes = HashMap::new();
+            for (column_id, column) in table_schema.t_cols.iter().enumerate() {
+                if column.c_type != Value::FOREIGN {
+                    indexes.insert(column_id, BTreeMap::new());
+                }
+            }
+
+            db.tables.push(DatabaseTable { table_schema, rows: Mutex::new(HashMap::new()), indexes: Mutex::new(indexes) });
             // mt.mutex_tables.push(Mutex::new(false));
         }
 
         let db = Database { /*mt,*/ db};
         db
     }
+
+    /// Claims the receiving end of a subscription's channel. Must be called once, by the
+    /// in-process caller that issued the matching `Command::Subscribe`, to start observing
+    /// `ChangeEvent`s; the handle returned by `Response::Subscribe` is otherwise inert.
+    pub fn take_subscription_receiver(&self, handle: u64) -> Option<Receiver<ChangeEvent>> {
+        self.db.pending_subscription_receivers.lock().unwrap().remove(&handle)
+    }
+
+    /// Produces a consistent point-in-time image of every table and `foreign_references_map`,
+    /// serialized to bytes that `Database::restore` can later load into a fresh `Database`.
+    /// `next_row_key` isn't part of the image: like `foreign_references_map` on the read side (see
+    /// `restore`), it's fully derivable from the restored rows (the max row id plus one), so rather
+    /// than serialize a counter that could drift from the rows it's sized to, `restore` recomputes
+    /// it instead. Locks are acquired in the same fixed order the rest of the engine uses (see
+    /// `handle_insert`/`handle_update`/`handle_drop`) -- `next_row_key` (held only to fix this
+    /// snapshot's point in time relative to concurrent inserts, never serialized), then
+    /// `foreign_references_map`, then each table's rows in table order. `next_row_key` is released
+    /// once the rest are taken; the others are held together for the duration of the copy so the
+    /// image can't observe a mutation straddling two tables.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let next_row_key = self.db.next_row_key.lock().unwrap();
+        let foreign_ref_map = self.db.foreign_references_map.lock().unwrap();
+        let table_rows: Vec<_> = self.db.tables.iter().map(|t| t.rows.lock().unwrap()).collect();
+        drop(next_row_key);
+
+        let mut out = Vec::new();
+
+        write_u32(&mut out, table_rows.len() as u32);
+        for rows in &table_rows {
+            write_u32(&mut out, rows.len() as u32);
+            for (id, row) in rows.iter() {
+                write_i64(&mut out, *id);
+                write_i64(&mut out, row.version);
+                write_u32(&mut out, row.data.len() as u32);
+                for value in &row.data {
+                    write_value(&mut out, value);
+                }
+            }
+        }
+
+        write_u32(&mut out, foreign_ref_map.len() as u32);
+        for (row_id, referencing) in foreign_ref_map.iter() {
+            write_i64(&mut out, *row_id);
+            write_u32(&mut out, referencing.len() as u32);
+            for (referencing_table_id, referencing_row_id) in referencing {
+                write_i32(&mut out, *referencing_table_id);
+                write_i64(&mut out, *referencing_row_id);
+            }
+        }
+
+        out
+    }
+
+    /// Copies a single table out of a running database without freezing the whole server: only
+    /// that table's `rows` lock is held, for the duration of serializing just its rows. Because
+    /// `next_row_key` and sibling tables aren't locked, concurrent mutation elsewhere proceeds
+    /// uninterrupted; the returned `versions` map (row id -> version at copy time) lets a caller
+    /// diff consecutive steps against a previous call's map to see which rows changed between
+    /// them, per the incremental backup mode this complements `snapshot`.
+    pub fn snapshot_table_step(&self, table_id: i32) -> Option<(Vec<u8>, HashMap<i64, i64>)> {
+        if !validate_table_id(&self.db.tables, table_id) {
+            return None;
+        }
+
+        let target_table = self.db.tables.get((table_id - 1) as usize).unwrap();
+        let rows = target_table.rows.lock().unwrap();
+
+        let mut out = Vec::new();
+        let mut versions = HashMap::new();
+        write_u32(&mut out, rows.len() as u32);
+        for (id, row) in rows.iter() {
+            write_i64(&mut out, *id);
+            write_i64(&mut out, row.version);
+            write_u32(&mut out, row.data.len() as u32);
+            for value in &row.data {
+                write_value(&mut out, value);
+            }
+            versions.insert(*id, row.version);
+        }
+
+        Some((out, versions))
+    }
+
+    /// Rebuilds a `Database` from bytes produced by `snapshot`, validated against the caller-
+    /// supplied `schema` (which must describe the same tables in the same order the snapshot was
+    /// taken in). `foreign_references_map` is not trusted from the serialized bytes -- it's
+    /// rebuilt from scratch by re-scanning every restored row's foreign values through
+    /// `get_all_referenced_rows`, the same logic `handle_insert` uses, so restore can't diverge
+    /// from what a sequence of inserts would have produced.
+    ///
+    /// Rows are loaded in two passes. The first only checks each value's type against the schema
+    /// and commits it to its table's `rows` -- unlike `handle_insert`, full foreign-key validation
+    /// can't happen yet, since a row may reference a row in a table that hasn't been loaded off
+    /// the wire yet (or a not-yet-inserted row later in its own table, for a self-referential
+    /// column). The second pass, once every table is loaded, re-checks every row's foreign values
+    /// against the now-complete tables, the same check `handle_insert` performs before accepting a
+    /// row -- with each table's `rows` lock only held long enough to clone the rows out, never
+    /// while `validate_values_against_schema` is re-locking (possibly that same table) to look up
+    /// a referenced row.
+    pub fn restore(bytes: &[u8], schema: Vec<TableMetadata>) -> Result<Database, i32> {
+        let mut cursor = 0usize;
+
+        let table_count = read_u32(bytes, &mut cursor).ok_or(Response::BAD_REQUEST)? as usize;
+        if table_count != schema.len() {
+            return Err(Response::BAD_TABLE);
+        }
+
+        let db = Database::new(schema);
+        let mut max_row_key = 0i64;
+
+        for target_table in &db.db.tables {
+            let row_count = read_u32(bytes, &mut cursor).ok_or(Response::BAD_REQUEST)?;
+            let target_table_cols = &target_table.table_schema.t_cols;
+            let mut rows = target_table.rows.lock().unwrap();
+
+            for _ in 0..row_count {
+                let id = read_i64(bytes, &mut cursor).ok_or(Response::BAD_REQUEST)?;
+                let version = read_i64(bytes, &mut cursor).ok_or(Response::BAD_REQUEST)?;
+                let value_count = read_u32(bytes, &mut cursor).ok_or(Response::BAD_REQUEST)?;
+
+                let mut data = Vec::with_capacity(value_count as usize);
+                for _ in 0..value_count {
+                    data.push(read_value(bytes, &mut cursor).ok_or(Response::BAD_REQUEST)?);
+                }
+
+                if let Err(error_code) = validate_value_types_against_schema(&data, target_table_cols) {
+                    return Err(error_code);
+                }
+
+                add_row_to_indexes(&target_table.indexes, &data, id);
+                max_row_key = max_row_key.max(id);
+                rows.insert(id, DatabaseTableRow { version, data });
+            }
+        }
+
+        for target_table in &db.db.tables {
+            let table_cols = &target_table.table_schema.t_cols;
+            let loaded_rows: Vec<Vec<Value>> = {
+                let rows = target_table.rows.lock().unwrap();
+                rows.values().map(|row| row.data.clone()).collect()
+            };
+
+            for data in &loaded_rows {
+                if let Err(error_code) = validate_values_against_schema(data, table_cols, &db.db) {
+                    return Err(error_code);
+                }
+            }
+        }
+
+        // Re-derive foreign_references_map from the restored rows rather than trusting the
+        // snapshot's copy of it
+        let mut foreign_ref_map = db.db.foreign_references_map.lock().unwrap();
+        for (table_index, target_table) in db.db.tables.iter().enumerate() {
+            let rows = target_table.rows.lock().unwrap();
+            for (id, row) in rows.iter() {
+                let referenced_rows = get_all_referenced_rows(&row.data);
+                add_to_foreign_reference_map(&mut *foreign_ref_map, &referenced_rows, *id, (table_index + 1) as i32);
+            }
+        }
+        drop(foreign_ref_map);
+
+        *db.db.next_row_key.lock().unwrap() = max_row_key + 1;
+
+        Ok(db)
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_i64(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0),
+        Value::Integer(v) => { out.push(1); write_i64(out, *v); },
+        Value::Float(v) => { out.push(2); out.extend_from_slice(&v.to_le_bytes()); },
+        Value::Text(v) => {
+            out.push(3);
+            write_u32(out, v.len() as u32);
+            out.extend_from_slice(v.as_bytes());
+        },
+        Value::Foreign(v) => { out.push(4); write_i64(out, *v); },
+    }
+}
+
+fn read_u32(bytes: &[u8], cursor: &mut usize) -> Option<u32> {
+    let slice = bytes.get(*cursor..*cursor + 4)?;
+    *cursor += 4;
+    Some(u32::from_le_bytes(slice.try_into().unwrap()))
+}
+
+fn read_i64(bytes: &[u8], cursor: &mut usize) -> Option<i64> {
+    let slice = bytes.get(*cursor..*cursor + 8)?;
+    *cursor += 8;
+    Some(i64::from_le_bytes(slice.try_into().unwrap()))
+}
+
+fn read_f64(bytes: &[u8], cursor: &mut usize) -> Option<f64> {
+    let slice = bytes.get(*cursor..*cursor + 8)?;
+    *cursor += 8;
+    Some(f64::from_le_bytes(slice.try_into().unwrap()))
+}
+
+fn read_value(bytes: &[u8], cursor: &mut usize) -> Option<Value> {
+    let tag = *bytes.get(*cursor)?;
+    *cursor += 1;
+    match tag {
+        0 => Some(Value::Null),
+        1 => Some(Value::Integer(read_i64(bytes, cursor)?)),
+        2 => Some(Value::Float(read_f64(bytes, cursor)?)),
+        3 => {
+            let len = read_u32(bytes, cursor)? as usize;
+            let slice = bytes.get(*cursor..*cursor + len)?;
+            *cursor += len;
+            Some(Value::Text(String::from_utf8(slice.to_vec()).ok()?))
+        },
+        4 => Some(Value::Foreign(read_i64(bytes, cursor)?)),
+        _ => None,
+    }
 }
 
 /* Receive the request packet from client and send a response back */
 pub fn handle_request(request: Request, db: &Database)
                       -> Response {
     /* Handle a valid request */
+    // A non-zero txn_id scopes the request to an open transaction the same way a non-zero
+    // `version` in Command::Update scopes it to a specific prior row version: 0 means "no active
+    // transaction", so mutations apply (and commit) immediately, exactly as they did before
+    // transactions existed.
+    let txn_id = request.txn_id;
+
     let result = match request.command {
-        Command::Insert(values) => handle_insert(db, request.table_id, values),
-        Command::Update(id, version, values) => handle_update(db, request.table_id, id, version, values),
-        Command::Drop(id) => handle_drop(db, request.table_id, id),
+        Command::Insert(values) => handle_insert(db, request.table_id, values, txn_id),
+        Command::Update(id, version, values) => handle_update(db, request.table_id, id, version, values, txn_id),
+        Command::Drop(id) => handle_drop(db, request.table_id, id, txn_id),
         Command::Get(id) => handle_get(db, request.table_id, id),
         Command::Query(column_id, operator, value) => handle_query(db, request.table_id, column_id, operator, value),
+        Command::BatchInsert(rows) => handle_batch_insert(db, request.table_id, rows, txn_id),
+        Command::BatchGet(ids) => handle_batch_get(db, request.table_id, ids),
+        Command::BatchDrop(ids) => handle_batch_drop(db, request.table_id, ids, txn_id),
+        Command::Subscribe(column_id, operator, value) => handle_subscribe(db, request.table_id, column_id, operator, value),
+        Command::Unsubscribe(handle) => handle_unsubscribe(db, request.table_id, handle),
+        Command::Join(column_id, operator, value) => handle_join(db, request.table_id, column_id, operator, value),
+        Command::Begin => handle_begin(db),
+        Command::Commit => handle_commit(db, txn_id),
+        Command::Rollback => handle_rollback(db, txn_id),
+        Command::Savepoint => handle_savepoint(db, txn_id),
+        Command::ReleaseSavepoint(handle) => handle_release_savepoint(db, txn_id, handle),
+        Command::RollbackToSavepoint(handle) => handle_rollback_to_savepoint(db, txn_id, handle),
         /* should never get here */
         Command::Exit => Err(Response::UNIMPLEMENTED),
     };
@@ -97,7 +429,7 @@ pub fn handle_request(request: Request, db: &Database)
     }
 }
 
-fn handle_insert(db: &Database, table_id: i32, values: Vec<Value>)
+fn handle_insert(db: &Database, table_id: i32, values: Vec<Value>, txn_id: i64)
                  -> Result<Response, i32> {
     // Ensure that the specified table ID actually references a valid table
     if !validate_table_id(&db.db.tables, table_id) {
@@ -136,12 +468,111 @@ fn handle_insert(db: &Database, table_id: i32, values: Vec<Value>)
 
     // Now insert the row into the table
     let mut target_table_rows = target_table.rows.lock().unwrap();
+    add_row_to_indexes(&target_table.indexes, &values, inserted_row_key);
+    notify_subscribers(&db.db, table_id, &values, || ChangeEvent::Added(inserted_row_key, 1, values.clone()));
     target_table_rows.insert(inserted_row_key, DatabaseTableRow { version: 1, data: values });
 
+    record_undo(&db.db, txn_id, UndoOp::UndoInsert { table_id, row_id: inserted_row_key });
+
     Ok(Response::Insert(inserted_row_key, 1))
 }
 
-fn handle_update(db: &Database, table_id: i32, object_id: i64, version: i64, new_values: Vec<Value>)
+/// Inserts many rows under a single acquisition of `next_row_key`, the target table's `rows`,
+/// and `foreign_references_map`, instead of paying the per-row lock/unlock cost of repeated
+/// `handle_insert` calls.
+///
+/// Every row is validated against the schema before any lock is taken. If any row fails
+/// validation, the whole batch is rejected and no rows are inserted (same all-or-nothing
+/// semantics a client would get by checking each row itself before sending it) -- rather than
+/// inserting a partial prefix and reporting per-row failures, which would leave the caller unsure
+/// which of its rows made it in without inspecting the response in detail.
+fn handle_batch_insert(db: &Database, table_id: i32, rows: Vec<Vec<Value>>, txn_id: i64)
+                       -> Result<Response, i32> {
+    // Ensure that the specified table ID actually references a valid table
+    if !validate_table_id(&db.db.tables, table_id) {
+        return Err(Response::BAD_TABLE);
+    }
+
+    let table_id_0_based = (table_id - 1) as usize;
+    let target_table = db.db.tables.get(table_id_0_based).unwrap();
+    let target_table_cols = &target_table.table_schema.t_cols;
+
+    // Validate every row up front so a bad row aborts the whole batch before any state changes
+    for values in &rows {
+        if let Err(error_code) = validate_values_against_schema(values, target_table_cols, &db.db) {
+            return Err(error_code);
+        }
+    }
+
+    // Acquire next_row_key, foreign_references_map, and the table's rows once for the whole
+    // batch instead of once per row
+    let mut row_key_mutex = db.db.next_row_key.lock().unwrap();
+    let first_row_key = *row_key_mutex;
+    *row_key_mutex += rows.len() as i64;
+    drop(row_key_mutex);
+
+    let mut foreign_ref_map = db.db.foreign_references_map.lock().unwrap();
+    let mut target_table_rows = target_table.rows.lock().unwrap();
+
+    let mut inserted = Vec::with_capacity(rows.len());
+    for (offset, values) in rows.into_iter().enumerate() {
+        let row_key = first_row_key + offset as i64;
+
+        let foreign_referenced_rows = get_all_referenced_rows(&values);
+        add_to_foreign_reference_map(&mut *foreign_ref_map, &foreign_referenced_rows, row_key, table_id);
+
+        add_row_to_indexes(&target_table.indexes, &values, row_key);
+        notify_subscribers(&db.db, table_id, &values, || ChangeEvent::Added(row_key, 1, values.clone()));
+        target_table_rows.insert(row_key, DatabaseTableRow { version: 1, data: values });
+
+        record_undo(&db.db, txn_id, UndoOp::UndoInsert { table_id, row_id: row_key });
+
+        inserted.push((row_key, 1));
+    }
+
+    Ok(Response::BatchInsert(inserted))
+}
+
+/// Fetches many rows under a single acquisition of the target table's `rows` lock. Row IDs that
+/// don't exist are reported per-row as `None` rather than aborting the whole batch, since a
+/// missing row is an expected, independent outcome for each id (unlike a malformed insert, which
+/// indicates a client bug worth rejecting outright).
+fn handle_batch_get(db: &Database, table_id: i32, ids: Vec<i64>)
+                    -> Result<Response, i32> {
+    if !validate_table_id(&db.db.tables, table_id) {
+        return Err(Response::BAD_TABLE);
+    }
+
+    let table_id_0_based = (table_id - 1) as usize;
+    let target_table = db.db.tables.get(table_id_0_based).unwrap();
+    let target_table_rows = target_table.rows.lock().unwrap();
+
+    let results = ids.iter().map(|id| {
+        target_table_rows.get(id).map(|row| (row.version, row.data.clone()))
+    }).collect();
+
+    Ok(Response::BatchGet(results))
+}
+
+/// Drops many rows under a single acquisition of `foreign_references_map`, reusing `drop_helper`
+/// (and its cascading-delete behaviour) for each id in turn. Per-id failures (e.g. NOT_FOUND) are
+/// reported in the result vector rather than aborting the rest of the batch, matching
+/// `handle_batch_get`'s per-row semantics.
+fn handle_batch_drop(db: &Database, table_id: i32, ids: Vec<i64>, txn_id: i64)
+                     -> Result<Response, i32> {
+    let mut foreign_ref_map = db.db.foreign_references_map.lock().unwrap();
+
+    let results = ids.into_iter().map(|id| {
+        match drop_helper(&db.db, &mut foreign_ref_map, table_id, id, txn_id) {
+            Ok(_) => None,
+            Err(code) => Some(code),
+        }
+    }).collect();
+
+    Ok(Response::BatchDrop(results))
+}
+
+fn handle_update(db: &Database, table_id: i32, object_id: i64, version: i64, new_values: Vec<Value>, txn_id: i64)
                  -> Result<Response, i32> {
     // Ensure that the specified table ID actually references a valid table
     if !validate_table_id(&db.db.tables, table_id) {
@@ -179,6 +610,7 @@ fn handle_update(db: &Database, table_id: i32, object_id: i64, version: i64, new
         return Err(Response::TXN_ABORT)
     }
 
+    let prior_row = target_row.clone();
     let prev_foreign_referenced_rows = get_all_referenced_rows(&target_row.data);
     let new_foreign_referenced_rows = get_all_referenced_rows(&new_values);
     // Before updating the table, we update the database's foreign reference map. This involves two steps:
@@ -195,29 +627,41 @@ fn handle_update(db: &Database, table_id: i32, object_id: i64, version: i64, new
     // Now we can update the row in the database
     let new_version_num = version + 1;
 
+    // Update the secondary indexes the same way as the foreign reference map above: remove the
+    // row's old values, then add its new ones.
+    remove_row_from_indexes(&target_table.indexes, &target_row.data, object_id);
+    add_row_to_indexes(&target_table.indexes, &new_values, object_id);
+    notify_subscribers(&db.db, table_id, &new_values, || ChangeEvent::Updated(object_id, new_version_num, new_values.clone()));
+
     // Update the row in the database
     if let Some(db_table_row) = target_table_rows.get_mut(&object_id) {
         db_table_row.data = new_values;
         db_table_row.version = new_version_num;
     }
 
+    record_undo(&db.db, txn_id, UndoOp::UndoUpdate { table_id, row_id: object_id, prior_row });
+
     Ok(Response::Update(new_version_num))
 }
 
-fn handle_drop(db: &Database, table_id: i32, object_id: i64)
+fn handle_drop(db: &Database, table_id: i32, object_id: i64, txn_id: i64)
                -> Result<Response, i32> {
 
     // let foreign_ref_map_mutex = db.mt.mutex_foreign_ref_map.lock().unwrap();
     let mut foreign_ref_map = db.db.foreign_references_map.lock().unwrap();
 
-    let ret = drop_helper(&db.db.tables, &mut foreign_ref_map, table_id, object_id);
+    let ret = drop_helper(&db.db, &mut foreign_ref_map, table_id, object_id, txn_id);
 
     ret
 }
 
-fn drop_helper(db_tables: &Vec<DatabaseTable>, foreign_ref_map: &mut MutexGuard<HashMap<i64, HashSet<(i32, i64)>>>, table_id: i32, object_id: i64) -> Result<Response, i32> {
+/// Removes a row (and, recursively, every row that foreign-references it). When `txn_id` names an
+/// open transaction, every row actually removed -- including each row removed by the cascade --
+/// pushes its own `UndoOp::UndoDrop` onto that transaction's undo log, so a later Rollback can
+/// restore the exact set of rows this call deleted, not just the one the caller asked for.
+fn drop_helper(db_data: &DatabaseData, foreign_ref_map: &mut MutexGuard<HashMap<i64, HashSet<(i32, i64)>>>, table_id: i32, object_id: i64, txn_id: i64) -> Result<Response, i32> {
     // Ensure that the specified table ID actually references a valid table
-    if !validate_table_id(db_tables, table_id) {
+    if !validate_table_id(&db_data.tables, table_id) {
         return Err(Response::BAD_TABLE);
     }
 
@@ -226,26 +670,33 @@ fn drop_helper(db_tables: &Vec<DatabaseTable>, foreign_ref_map: &mut MutexGuard<
     let table_id_0_based = (table_id - 1) as usize;
 
     //let table_mutex = db.mt.mutex_tables.get_mut(table_id_0_based).unwrap().lock().unwrap();
-    let target_table = db_tables.get(table_id_0_based).unwrap();
+    let target_table = db_data.tables.get(table_id_0_based).unwrap();
     let mut target_table_rows = target_table.rows.lock().unwrap();
 
     // Remove the specified row from the table
     let removed_row = target_table_rows.remove(&object_id);
-    if let None = removed_row {
-        return Err(Response::NOT_FOUND);
-    }
+    let removed_row = match removed_row {
+        Some(row) => row,
+        None => return Err(Response::NOT_FOUND),
+    };
+
+    remove_row_from_indexes(&target_table.indexes, &removed_row.data, object_id);
+    notify_subscribers(db_data, table_id, &removed_row.data, || ChangeEvent::Removed(object_id));
 
     // Get a list of all rows that made a foreign reference to the row we just deleted
-    let referencing_rows = foreign_ref_map.remove(&object_id);
-    let referencing_rows = match referencing_rows {
-        Some(row_ids) => row_ids,
-        _ => return Ok(Response::Drop)
-    };
+    let referencing_rows = foreign_ref_map.remove(&object_id).unwrap_or_default();
+
+    record_undo(db_data, txn_id, UndoOp::UndoDrop {
+        table_id,
+        row_id: object_id,
+        row: removed_row,
+        referencing_rows: referencing_rows.clone(),
+    });
 
     // Recursively delete these rows
     for (referencing_table_id, referencing_row_id) in referencing_rows {
         // Recursively drop the referenced row
-        let _ = drop_helper(db_tables, foreign_ref_map, referencing_table_id, referencing_row_id);
+        let _ = drop_helper(db_data, foreign_ref_map, referencing_table_id, referencing_row_id, txn_id);
     }
 
     //drop(table_mutex);
@@ -359,40 +810,41 @@ fn handle_query(db: &Database, table_id: i32, column_id: i32, operator: i32, oth
         },
     };
 
+    // Only foreign columns are excluded from indexing (see DatabaseTable::indexes); column_id
+    // 0 is indexed the same as any other column, so it has to agree with the scan below too.
+    let indexes = target_table.indexes.lock().unwrap();
+    let index = indexes.get(&column_id);
+
     match operator {
-        OP_EQ => 
-                for (id, row) in target_table_rows.iter() {
-                    if column_id == 0 {
-                        // it is possible to scan a table for id of a row, using column_id 0
-                        if let Value::Integer(value) = other{
-                            if *id == value {
-                                res.push(*id);
-                            }
-                        }
+        OP_EQ =>
+                if let Some(index) = index {
+                    if let Some(row_ids) = index.get(&other) {
+                        res.extend(row_ids.iter().copied());
+                    }
+                }
+                else {
+                    for (id, row) in target_table_rows.iter() {
+                        if row.data[column_id as usize] == other {
+                            res.push(*id)
+                        };
                     }
-                    if row.data[column_id as usize] == other {
-                        res.push(*id)
-                    };                
                 },
-        OP_NE => 
+        OP_NE =>
                 for (id, row) in target_table_rows.iter() {
-                    if column_id == 0 {
-                        // it is possible to scan a table for id of a row, using column_id 0
-                        if let Value::Integer(value) = other{
-                            if *id == value {
-                                res.push(*id);
-                            }
-                        }
-                    }
                     if row.data[column_id as usize] != other {
                         res.push(*id)
-                    };                
+                    };
                 },
         OP_LT => // column id and foreign fields only supported EQ and NE operators.
                 // return error for all other operator types
                 if target_col_type == Value::FOREIGN || column_id == 0 {
                     return Err(Response::BAD_QUERY);
                 }
+                else if let Some(index) = index {
+                    for (_, row_ids) in index.range(..other) {
+                        res.extend(row_ids.iter().copied());
+                    }
+                }
                 else {
                     for (id, row) in target_table_rows.iter() {
                         if row.data[column_id as usize] < other {
@@ -405,6 +857,11 @@ fn handle_query(db: &Database, table_id: i32, column_id: i32, operator: i32, oth
                 if target_col_type == Value::FOREIGN || column_id == 0 {
                     return Err(Response::BAD_QUERY);
                 }
+                else if let Some(index) = index {
+                    for (_, row_ids) in index.range((std::ops::Bound::Excluded(other.clone()), std::ops::Bound::Unbounded)) {
+                        res.extend(row_ids.iter().copied());
+                    }
+                }
                 else {
                     for (id, row) in target_table_rows.iter() {
                         if row.data[column_id as usize] > other {
@@ -417,6 +874,11 @@ fn handle_query(db: &Database, table_id: i32, column_id: i32, operator: i32, oth
                 if target_col_type == Value::FOREIGN || column_id == 0 {
                     return Err(Response::BAD_QUERY);
                 }
+                else if let Some(index) = index {
+                    for (_, row_ids) in index.range((std::ops::Bound::Unbounded, std::ops::Bound::Included(other.clone()))) {
+                        res.extend(row_ids.iter().copied());
+                    }
+                }
                 else {
                     for (id, row) in target_table_rows.iter() {
                         if row.data[column_id as usize] <= other {
@@ -429,6 +891,11 @@ fn handle_query(db: &Database, table_id: i32, column_id: i32, operator: i32, oth
                 if target_col_type == Value::FOREIGN || column_id == 0 {
                     return Err(Response::BAD_QUERY);
                 }
+                else if let Some(index) = index {
+                    for (_, row_ids) in index.range(other.clone()..) {
+                        res.extend(row_ids.iter().copied());
+                    }
+                }
                 else {
                     for (id, row) in target_table_rows.iter() {
                         if row.data[column_id as usize] >= other {
@@ -436,7 +903,7 @@ fn handle_query(db: &Database, table_id: i32, column_id: i32, operator: i32, oth
                         }
                     }
                 },
-        // checks if operator number is valid 
+        // checks if operator number is valid
         _ => return Err(Response::BAD_QUERY)
     };
 
@@ -445,6 +912,398 @@ fn handle_query(db: &Database, table_id: i32, column_id: i32, operator: i32, oth
     Ok(Response::Query(res))
 }
 
+/// Adds `row_id` to the secondary index of every indexed column in `values`
+
+fn add_row_to_indexes(indexes: &Mutex<HashMap<usize, BTreeMap<Value, HashSet<i64>>>>, values: &Vec<Value>, row_id: i64) {
+    let mut indexes = indexes.lock().unwrap();
+    for (column_id, index) in indexes.iter_mut() {
+        index.entry(values[*column_id].clone()).or_insert_with(HashSet::new).insert(row_id);
+    }
+}
+
+/// Removes `row_id` from the secondary index of every indexed column in `values`
+
+fn remove_row_from_indexes(indexes: &Mutex<HashMap<usize, BTreeMap<Value, HashSet<i64>>>>, values: &Vec<Value>, row_id: i64) {
+    let mut indexes = indexes.lock().unwrap();
+    for (column_id, index) in indexes.iter_mut() {
+        if let Some(row_ids) = index.get_mut(&values[*column_id]) {
+            row_ids.remove(&row_id);
+            if row_ids.is_empty() {
+                index.remove(&values[*column_id]);
+            }
+        }
+    }
+}
+
+/// Registers a standing query: the table and `column_id` are validated like `handle_query`
+/// (OP_AL with column_id 0 matches every row of the table), but the operator and the value's type
+/// are not checked -- an unknown operator matches no row. Instead of evaluating immediately, the
+/// predicate is stored so future inserts/updates/drops on this table are sent over a new channel
+/// whose receiver is parked for `Database::take_subscription_receiver`.
+fn handle_subscribe(db: &Database, table_id: i32, column_id: i32, operator: i32, value: Value)
+                    -> Result<Response, i32> {
+    if !validate_table_id(&db.db.tables, table_id) {
+        return Err(Response::BAD_TABLE);
+    }
+
+    let predicate = if operator == OP_AL {
+        if column_id != 0 {
+            return Err(Response::BAD_QUERY);
+        }
+        SubscriptionPredicate::All
+    }
+    else {
+        let target_table = db.db.tables.get((table_id - 1) as usize).unwrap();
+        if !validate_column_id(target_table, column_id) {
+            return Err(Response::BAD_QUERY);
+        }
+        SubscriptionPredicate::Column { column_id: (column_id - 1) as usize, operator, value }
+    };
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let mut next_id = db.db.next_subscription_id.lock().unwrap();
+    let handle = *next_id;
+    *next_id += 1;
+    drop(next_id);
+
+    let mut subscriptions = db.db.subscriptions.lock().unwrap();
+    subscriptions.entry(table_id).or_insert_with(HashMap::new).insert(handle, Subscription { predicate, sender });
+    drop(subscriptions);
+
+    db.db.pending_subscription_receivers.lock().unwrap().insert(handle, receiver);
+
+    Ok(Response::Subscribe(handle))
+}
+
+fn handle_unsubscribe(db: &Database, table_id: i32, handle: u64)
+                      -> Result<Response, i32> {
+    let mut subscriptions = db.db.subscriptions.lock().unwrap();
+    let removed = subscriptions.get_mut(&table_id).and_then(|table_subs| table_subs.remove(&handle));
+
+    match removed {
+        Some(_) => Ok(Response::Unsubscribe),
+        None => Err(Response::NOT_FOUND),
+    }
+}
+
+/// Opens a new transaction and returns its id. The id is meaningless to the database until the
+/// caller starts passing it back as `Request::txn_id` on subsequent Insert/Update/Drop/BatchInsert/
+/// BatchDrop requests, the same way a `Request::table_id` of 0 would simply never match a table.
+fn handle_begin(db: &Database) -> Result<Response, i32> {
+    let mut next_id = db.db.next_txn_id.lock().unwrap();
+    let txn_id = *next_id;
+    *next_id += 1;
+    drop(next_id);
+
+    db.db.transactions.lock().unwrap().insert(txn_id, Transaction { undo_log: Vec::new(), savepoints: HashMap::new() });
+
+    Ok(Response::Begin(txn_id))
+}
+
+/// Discards the transaction's undo log -- its mutations are already visible in the tables (they
+/// applied immediately, each recording an inverse in case of a future Rollback), so committing is
+/// just forgetting how to undo them.
+fn handle_commit(db: &Database, txn_id: i64) -> Result<Response, i32> {
+    match db.db.transactions.lock().unwrap().remove(&txn_id) {
+        Some(_) => Ok(Response::Commit),
+        None => Err(Response::NOT_FOUND),
+    }
+}
+
+/// Replays the transaction's undo log in reverse, restoring the database to the state it was in
+/// before the transaction began, then discards the transaction.
+fn handle_rollback(db: &Database, txn_id: i64) -> Result<Response, i32> {
+    let transaction = db.db.transactions.lock().unwrap().remove(&txn_id);
+    let transaction = match transaction {
+        Some(transaction) => transaction,
+        None => return Err(Response::NOT_FOUND),
+    };
+
+    undo_to(&db.db, transaction.undo_log);
+
+    Ok(Response::Rollback)
+}
+
+/// Marks the current position in the transaction's undo log so `RollbackToSavepoint` can unwind
+/// back to it independently, without ending the enclosing transaction.
+fn handle_savepoint(db: &Database, txn_id: i64) -> Result<Response, i32> {
+    let mut next_id = db.db.next_txn_id.lock().unwrap();
+    let handle = *next_id as u64;
+    *next_id += 1;
+    drop(next_id);
+
+    let mut transactions = db.db.transactions.lock().unwrap();
+    let transaction = match transactions.get_mut(&txn_id) {
+        Some(transaction) => transaction,
+        None => return Err(Response::NOT_FOUND),
+    };
+
+    transaction.savepoints.insert(handle, transaction.undo_log.len());
+
+    Ok(Response::Savepoint(handle))
+}
+
+/// Forgets a savepoint marker without touching the undo log -- the mutations recorded since the
+/// savepoint remain part of the enclosing transaction and are still undone by a later Rollback.
+fn handle_release_savepoint(db: &Database, txn_id: i64, handle: u64) -> Result<Response, i32> {
+    let mut transactions = db.db.transactions.lock().unwrap();
+    let transaction = match transactions.get_mut(&txn_id) {
+        Some(transaction) => transaction,
+        None => return Err(Response::NOT_FOUND),
+    };
+
+    match transaction.savepoints.remove(&handle) {
+        Some(_) => Ok(Response::ReleaseSavepoint),
+        None => Err(Response::NOT_FOUND),
+    }
+}
+
+/// Replays the undo log back down to the position recorded by `handle`, undoing every mutation
+/// made since that savepoint while keeping the transaction (and the savepoint itself, so it can be
+/// rolled back to again) open.
+fn handle_rollback_to_savepoint(db: &Database, txn_id: i64, handle: u64) -> Result<Response, i32> {
+    let mut transactions = db.db.transactions.lock().unwrap();
+    let transaction = match transactions.get_mut(&txn_id) {
+        Some(transaction) => transaction,
+        None => return Err(Response::NOT_FOUND),
+    };
+
+    let target_len = match transaction.savepoints.get(&handle) {
+        Some(target_len) => *target_len,
+        None => return Err(Response::NOT_FOUND),
+    };
+
+    let to_undo = transaction.undo_log.split_off(target_len);
+    // Any savepoint taken after this one is now past the end of the log and can't be rolled back
+    // to again
+    transaction.savepoints.retain(|_, len| *len <= target_len);
+    drop(transactions);
+
+    undo_to(&db.db, to_undo);
+
+    Ok(Response::RollbackToSavepoint)
+}
+
+/// Records the inverse of a mutation onto the transaction's undo log, if `txn_id` is 0 (no open
+/// transaction) this is a no-op, matching the convention `Request::txn_id` == 0 means the
+/// mutation applies outside of any transaction and there is nothing to undo it later.
+fn record_undo(db_data: &DatabaseData, txn_id: i64, op: UndoOp) {
+    if txn_id == 0 {
+        return;
+    }
+
+    if let Some(transaction) = db_data.transactions.lock().unwrap().get_mut(&txn_id) {
+        transaction.undo_log.push(op);
+    }
+}
+
+/// Applies the inverse of every entry in `undo_log`, from most recent to oldest, restoring the
+/// rows to their state before those entries were recorded. For inserts, `next_row_key` is rewound
+/// too, but only while no later key has been allocated since (see `UndoOp::UndoInsert` below).
+fn undo_to(db_data: &DatabaseData, undo_log: Vec<UndoOp>) {
+    for op in undo_log.into_iter().rev() {
+        match op {
+            UndoOp::UndoInsert { table_id, row_id } => {
+                let target_table = db_data.tables.get((table_id - 1) as usize).unwrap();
+
+                // Lock foreign_references_map before rows, matching the order every other
+                // mutator (handle_update, drop_helper) uses -- locking them the other way around
+                // here let a concurrent Rollback/RollbackToSavepoint deadlock against any
+                // concurrent insert/update/drop.
+                let mut foreign_ref_map = db_data.foreign_references_map.lock().unwrap();
+                let mut rows = target_table.rows.lock().unwrap();
+                if let Some(row) = rows.remove(&row_id) {
+                    remove_row_from_indexes(&target_table.indexes, &row.data, row_id);
+                    let referenced_rows = get_all_referenced_rows(&row.data);
+                    remove_from_foreign_reference_map(&mut *foreign_ref_map, &referenced_rows, row_id, table_id);
+                }
+                drop(rows);
+                drop(foreign_ref_map);
+
+                // Other connections can consume keys from next_row_key between this row's insert
+                // and this rollback, so rewinding unconditionally risks handing the same key to a
+                // future insert while a row it already clobbered is still live. Only rewind when
+                // row_id was the most recently allocated key (nothing since has advanced the
+                // counter past it) -- which a whole batch's worth of these entries still satisfies
+                // one row at a time, in reverse allocation order, as undo_to replays them.
+                let mut next_row_key = db_data.next_row_key.lock().unwrap();
+                if *next_row_key == row_id + 1 {
+                    *next_row_key = row_id;
+                }
+            },
+            UndoOp::UndoUpdate { table_id, row_id, prior_row } => {
+                let target_table = db_data.tables.get((table_id - 1) as usize).unwrap();
+
+                // Same lock-order fix as UndoInsert above: foreign_references_map before rows.
+                let mut foreign_ref_map = db_data.foreign_references_map.lock().unwrap();
+                let mut rows = target_table.rows.lock().unwrap();
+                if let Some(current) = rows.get(&row_id) {
+                    let current_referenced = get_all_referenced_rows(&current.data);
+                    let prior_referenced = get_all_referenced_rows(&prior_row.data);
+                    remove_from_foreign_reference_map(&mut *foreign_ref_map, &current_referenced, row_id, table_id);
+                    add_to_foreign_reference_map(&mut *foreign_ref_map, &prior_referenced, row_id, table_id);
+
+                    remove_row_from_indexes(&target_table.indexes, &current.data, row_id);
+                    add_row_to_indexes(&target_table.indexes, &prior_row.data, row_id);
+                }
+                rows.insert(row_id, prior_row);
+            },
+            UndoOp::UndoDrop { table_id, row_id, row, referencing_rows } => {
+                let target_table = db_data.tables.get((table_id - 1) as usize).unwrap();
+                add_row_to_indexes(&target_table.indexes, &row.data, row_id);
+                target_table.rows.lock().unwrap().insert(row_id, row);
+
+                let mut foreign_ref_map = db_data.foreign_references_map.lock().unwrap();
+                if referencing_rows.is_empty() {
+                    foreign_ref_map.remove(&row_id);
+                }
+                else {
+                    foreign_ref_map.insert(row_id, referencing_rows);
+                }
+            },
+        }
+    }
+}
+
+/// Checks whether a row matches a subscription's predicate, using the same semantics as the
+/// corresponding operator in `handle_query`.
+fn matches_predicate(predicate: &SubscriptionPredicate, row_data: &Vec<Value>) -> bool {
+    match predicate {
+        SubscriptionPredicate::All => true,
+        SubscriptionPredicate::Column { column_id, operator, value } => {
+            let field = &row_data[*column_id];
+            match *operator {
+                OP_EQ => field == value,
+                OP_NE => field != value,
+                OP_LT => field < value,
+                OP_GT => field > value,
+                OP_LE => field <= value,
+                OP_GE => field >= value,
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Evaluates `row_data` against every subscription registered for `table_id` and pushes a change
+/// event to the ones that match. Called while the mutation that produced `row_data` is still
+/// linearized behind the caller's locks, so subscribers see events in the same order the
+/// mutations actually committed. A disconnected receiver (the subscriber dropped its handle
+/// without calling Unsubscribe) is left in place; the next Unsubscribe removes it, same as any
+/// other stale subscription.
+fn notify_subscribers(db: &DatabaseData, table_id: i32, row_data: &Vec<Value>, make_event: impl Fn() -> ChangeEvent) {
+    let subscriptions = db.subscriptions.lock().unwrap();
+    let table_subs = match subscriptions.get(&table_id) {
+        Some(table_subs) => table_subs,
+        None => return,
+    };
+
+    for subscription in table_subs.values() {
+        if matches_predicate(&subscription.predicate, row_data) {
+            let _ = subscription.sender.send(make_event());
+        }
+    }
+}
+
+/// Relational navigation across a foreign key: returns the row ids in `table_id` whose column
+/// `column_id` references a row in the referenced table that matches `operator`/`value`. Rather
+/// than scanning `table_id`, this reuses `foreign_references_map` as a join index, so the cost is
+/// proportional to the number of matching referenced rows and their referencers, not the product
+/// of both tables' sizes.
+fn handle_join(db: &Database, table_id: i32, column_id: i32, operator: i32, value: Value)
+               -> Result<Response, i32> {
+    if !validate_table_id(&db.db.tables, table_id) {
+        return Err(Response::BAD_TABLE);
+    }
+
+    let target_table = db.db.tables.get((table_id - 1) as usize).unwrap();
+    if !validate_column_id(target_table, column_id) {
+        return Err(Response::BAD_QUERY);
+    }
+
+    let column_id_0_based = (column_id - 1) as usize;
+    let join_column = &target_table.table_schema.t_cols[column_id_0_based];
+    if join_column.c_type != Value::FOREIGN {
+        return Err(Response::BAD_QUERY);
+    }
+
+    let referenced_table_id = join_column.c_ref;
+    if !validate_table_id(&db.db.tables, referenced_table_id) {
+        return Err(Response::BAD_TABLE);
+    }
+
+    // Match the referenced table's row ids directly against `operator`/`value` instead of going
+    // through handle_query: handle_query's column_id 0 path is only reachable for OP_AL (any
+    // other operator fails validate_column_id's col_id >= 1 check), so OP_EQ/OP_NE/range joins --
+    // the primary semi-join use case -- would always error out here.
+    let referenced_table = db.db.tables.get((referenced_table_id - 1) as usize).unwrap();
+    let matched_referenced_ids = {
+        let referenced_table_rows = referenced_table.rows.lock().unwrap();
+        query_row_ids_by_id(&referenced_table_rows, operator, &value)?
+    };
+
+    let foreign_ref_map = db.db.foreign_references_map.lock().unwrap();
+    let target_table_rows = target_table.rows.lock().unwrap();
+
+    let mut res: Vec<i64> = Vec::new();
+    for referenced_id in matched_referenced_ids {
+        let referencing_rows = match foreign_ref_map.get(&referenced_id) {
+            Some(referencing_rows) => referencing_rows,
+            None => continue,
+        };
+
+        for (referencing_table_id, referencing_row_id) in referencing_rows {
+            if *referencing_table_id != table_id {
+                continue;
+            }
+
+            // foreign_references_map doesn't record which column made the reference, so confirm
+            // it was specifically `column_id` before counting this row as a match
+            if let Some(row) = target_table_rows.get(referencing_row_id) {
+                if row.data[column_id_0_based] == Value::Foreign(referenced_id) {
+                    res.push(*referencing_row_id);
+                }
+            }
+        }
+    }
+
+    Ok(Response::Query(res))
+}
+
+/// Matches a table's row ids against `operator`/`value` the same way `handle_query`'s column_id 0
+/// path would, if that path weren't restricted to OP_AL by `validate_column_id`. Used by
+/// `handle_join` to evaluate the referenced-table side of a join without routing through
+/// `handle_query` (and its col_id >= 1 requirement for anything but OP_AL).
+fn query_row_ids_by_id(rows: &HashMap<i64, DatabaseTableRow>, operator: i32, value: &Value) -> Result<Vec<i64>, i32> {
+    if operator == OP_AL {
+        return Ok(rows.keys().copied().collect());
+    }
+
+    let target = match value {
+        Value::Integer(v) => *v,
+        _ => return Err(Response::BAD_QUERY),
+    };
+
+    let mut res = Vec::new();
+    for id in rows.keys() {
+        let matches = match operator {
+            OP_EQ => *id == target,
+            OP_NE => *id != target,
+            OP_LT => *id < target,
+            OP_GT => *id > target,
+            OP_LE => *id <= target,
+            OP_GE => *id >= target,
+            _ => return Err(Response::BAD_QUERY),
+        };
+        if matches {
+            res.push(*id);
+        }
+    }
+
+    Ok(res)
+}
+
 fn validate_table_id(db_tables: &Vec<DatabaseTable>, table_id: i32) -> bool {
     // Ensure that the specified table ID actually references a valid table
     return table_id >= 1 && table_id <= (db_tables.len() as i32);
@@ -455,7 +1314,12 @@ fn validate_column_id(target_table: &DatabaseTable, col_id: i32) -> bool {
     return col_id >= 1 && col_id <= (target_table.table_schema.t_cols.len() as i32);
 }
 
-fn validate_values_against_schema(values: &Vec<Value>, table_cols: &Vec<ColumnMetadata>, db: &DatabaseData) -> Result<bool, i32> {
+/// Checks that `values` has the right shape for `table_cols` -- right length, and each value's
+/// type matching its column's declared type. Doesn't touch any lock: unlike the foreign-key check
+/// in `validate_values_against_schema`, this never needs to look at another table's rows, so it's
+/// safe to call while holding a table's own `rows` lock (see `Database::restore`, which can't yet
+/// validate foreign references at that point because not every table has been loaded).
+fn validate_value_types_against_schema(values: &Vec<Value>, table_cols: &Vec<ColumnMetadata>) -> Result<(), i32> {
     // The number of values specified must match how many columns there are in the table to store these values
     if values.len() != table_cols.len() {
         return Err(Response::BAD_ROW);
@@ -484,24 +1348,37 @@ fn validate_values_against_schema(values: &Vec<Value>, table_cols: &Vec<ColumnMe
                     return Err(Response::BAD_VALUE);
                 }
             }
-            Value::Foreign(referenced_row_id) => {
+            Value::Foreign(_) => {
                 if table_cols[i].c_type != Value::FOREIGN {
                     return Err(Response::BAD_VALUE);
-                } else if referenced_row_id != 0 {
-                    // Fetch the referenced table ID and convert it to a 0-based index
-                    let referenced_table_index = (table_cols[i].c_ref - 1) as usize;
-
-                    // Check that the referenced row actually exists in the table that the column refers to
-                    let referenced_table = db.tables.get(referenced_table_index).unwrap();
-                    let referenced_table_rows = referenced_table.rows.lock().unwrap();
-                    if !referenced_table_rows.contains_key(&referenced_row_id) {
-                        return Err(Response::BAD_FOREIGN);
-                    }
                 }
             }
         };
     }
 
+    Ok(())
+}
+
+fn validate_values_against_schema(values: &Vec<Value>, table_cols: &Vec<ColumnMetadata>, db: &DatabaseData) -> Result<bool, i32> {
+    validate_value_types_against_schema(values, table_cols)?;
+
+    // Ensure that every foreign value actually references a row that exists
+    for i in 0..values.len() {
+        if let Value::Foreign(referenced_row_id) = values[i] {
+            if referenced_row_id != 0 {
+                // Fetch the referenced table ID and convert it to a 0-based index
+                let referenced_table_index = (table_cols[i].c_ref - 1) as usize;
+
+                // Check that the referenced row actually exists in the table that the column refers to
+                let referenced_table = db.tables.get(referenced_table_index).unwrap();
+                let referenced_table_rows = referenced_table.rows.lock().unwrap();
+                if !referenced_table_rows.contains_key(&referenced_row_id) {
+                    return Err(Response::BAD_FOREIGN);
+                }
+            }
+        }
+    }
+
     Ok(true)
 }
 
@@ -551,4 +1428,260 @@ fn remove_from_foreign_reference_map(foreign_ref_map: &mut HashMap<i64, HashSet<
     for foreign_reference_id in referenced_rows {
         foreign_ref_map.get_mut(foreign_reference_id).unwrap().remove(&(referencing_row_table_id, referencing_row_id));
     }
+}
+
+#[cfg(test)]
+mod query_index_tests {
+    use super::*;
+    use crate::schema::{ColumnMetadata, TableMetadata};
+
+    fn schema() -> Vec<TableMetadata> {
+        vec![TableMetadata {
+            t_name: "widgets".to_string(),
+            t_cols: vec![ColumnMetadata { c_name: "count".to_string(), c_type: Value::INTEGER, c_ref: 0 }],
+        }]
+    }
+
+    fn insert(db: &Database, count: i64) -> i64 {
+        match handle_request(Request { table_id: 1, txn_id: 0, command: Command::Insert(vec![Value::Integer(count)]) }, db) {
+            Response::Insert(id, _) => id,
+            _ => panic!("insert failed"),
+        }
+    }
+
+    fn query(db: &Database, operator: i32, value: Value) -> Vec<i64> {
+        match handle_request(Request { table_id: 1, txn_id: 0, command: Command::Query(1, operator, value) }, db) {
+            Response::Query(mut ids) => { ids.sort(); ids },
+            _ => panic!("query failed"),
+        }
+    }
+
+    // The indexed path (column_id != 0, which probes DatabaseTable::indexes) and the linear-scan
+    // fallback must agree on every operator -- this is what lets handle_query swap one for the
+    // other without changing observable behavior.
+    #[test]
+    fn indexed_query_matches_a_linear_scan_for_every_operator() {
+        let db = Database::new(schema());
+        let ids: Vec<i64> = [1, 3, 3, 5, 7].iter().map(|count| insert(&db, *count)).collect();
+
+        assert_eq!(query(&db, OP_EQ, Value::Integer(3)), { let mut v = vec![ids[1], ids[2]]; v.sort(); v });
+        assert_eq!(query(&db, OP_NE, Value::Integer(3)), { let mut v = vec![ids[0], ids[3], ids[4]]; v.sort(); v });
+        assert_eq!(query(&db, OP_LT, Value::Integer(5)), { let mut v = vec![ids[0], ids[1], ids[2]]; v.sort(); v });
+        assert_eq!(query(&db, OP_GT, Value::Integer(3)), { let mut v = vec![ids[3], ids[4]]; v.sort(); v });
+        assert_eq!(query(&db, OP_LE, Value::Integer(3)), { let mut v = vec![ids[0], ids[1], ids[2]]; v.sort(); v });
+        assert_eq!(query(&db, OP_GE, Value::Integer(5)), { let mut v = vec![ids[3], ids[4]]; v.sort(); v });
+
+        // An update must move a row between index buckets, not just leave a stale entry behind.
+        handle_request(Request { table_id: 1, txn_id: 0, command: Command::Update(ids[0], 0, vec![Value::Integer(100)]) }, &db);
+        assert_eq!(query(&db, OP_EQ, Value::Integer(1)), Vec::<i64>::new());
+        assert_eq!(query(&db, OP_EQ, Value::Integer(100)), vec![ids[0]]);
+
+        // A drop must remove the row from the index too.
+        handle_request(Request { table_id: 1, txn_id: 0, command: Command::Drop(ids[3]) }, &db);
+        assert_eq!(query(&db, OP_EQ, Value::Integer(5)), Vec::<i64>::new());
+    }
+
+    // Column 0 is indexed exactly like any other column. A query value that happens to equal a
+    // row's id, but not that row's actual value, must not spuriously match -- OP_EQ (indexed) and
+    // OP_NE (scan) have to agree here or callers would see the two operators contradict each other.
+    #[test]
+    fn first_column_query_does_not_confuse_row_id_with_the_queried_value() {
+        let db = Database::new(schema());
+        let ids: Vec<i64> = [10, 20, 30].iter().map(|count| insert(&db, *count)).collect();
+
+        // ids[0] is some small row id (e.g. 1) that doesn't equal any row's "count" value, so an
+        // id/value mix-up would make this query wrongly include it.
+        assert_eq!(query(&db, OP_EQ, Value::Integer(ids[0])), Vec::<i64>::new());
+        assert_eq!(query(&db, OP_NE, Value::Integer(ids[0])), { let mut v = ids.clone(); v.sort(); v });
+    }
+}
+
+#[cfg(test)]
+mod rollback_tests {
+    use super::*;
+    use crate::schema::{ColumnMetadata, TableMetadata};
+
+    fn schema() -> Vec<TableMetadata> {
+        vec![TableMetadata {
+            t_name: "widgets".to_string(),
+            t_cols: vec![ColumnMetadata { c_name: "name".to_string(), c_type: Value::STRING, c_ref: 0 }],
+        }]
+    }
+
+    fn request(table_id: i32, txn_id: i64, command: Command) -> Request {
+        Request { table_id, txn_id, command }
+    }
+
+    fn insert(db: &Database, txn_id: i64, name: &str) -> i64 {
+        match handle_request(request(1, txn_id, Command::Insert(vec![Value::Text(name.to_string())])), db) {
+            Response::Insert(id, _) => id,
+            _ => panic!("insert failed"),
+        }
+    }
+
+    #[test]
+    fn rollback_frees_the_key_when_nothing_else_consumed_it() {
+        let db = Database::new(schema());
+
+        let txn_id = match handle_request(request(1, 0, Command::Begin), &db) {
+            Response::Begin(id) => id,
+            _ => panic!("begin failed"),
+        };
+        let widget = insert(&db, txn_id, "gizmo");
+        handle_request(request(1, txn_id, Command::Rollback), &db);
+
+        assert!(matches!(handle_request(request(1, 0, Command::Get(widget)), &db), Response::Error(Response::NOT_FOUND)));
+
+        let reused = insert(&db, 0, "gadget");
+        assert_eq!(reused, widget);
+    }
+
+    #[test]
+    fn rollback_does_not_reuse_a_key_another_connection_already_took() {
+        let db = Database::new(schema());
+
+        let txn_id = match handle_request(request(1, 0, Command::Begin), &db) {
+            Response::Begin(id) => id,
+            _ => panic!("begin failed"),
+        };
+        let in_txn = insert(&db, txn_id, "gizmo");
+        // Simulates a second connection inserting, outside the transaction, before the first
+        // connection rolls back -- the live row this produces must not be clobbered.
+        let concurrent = insert(&db, 0, "widget");
+        handle_request(request(1, txn_id, Command::Rollback), &db);
+
+        match handle_request(request(1, 0, Command::Get(concurrent)), &db) {
+            Response::Get(_, data) => assert_eq!(data, vec![Value::Text("widget".to_string())]),
+            _ => panic!("the concurrently inserted row must survive the other connection's rollback"),
+        }
+
+        let next = insert(&db, 0, "sprocket");
+        assert_ne!(next, in_txn, "must not hand out a key the rolled-back row already used");
+        assert_ne!(next, concurrent, "must not hand out a key the live concurrent row already used");
+    }
+}
+
+#[cfg(test)]
+mod restore_tests {
+    use super::*;
+    use crate::schema::{ColumnMetadata, TableMetadata};
+
+    // A self-referencing "employees" table (a column whose c_ref points back at table 1) so the
+    // restored row ordering can't rely on every referenced row already being loaded into the
+    // table it validates against.
+    fn schema() -> Vec<TableMetadata> {
+        vec![TableMetadata {
+            t_name: "employees".to_string(),
+            t_cols: vec![
+                ColumnMetadata { c_name: "name".to_string(), c_type: Value::STRING, c_ref: 0 },
+                ColumnMetadata { c_name: "manager_id".to_string(), c_type: Value::FOREIGN, c_ref: 1 },
+            ],
+        }]
+    }
+
+    fn insert(db: &Database, table_id: i32, values: Vec<Value>) -> i64 {
+        match handle_request(Request { table_id, txn_id: 0, command: Command::Insert(values) }, db) {
+            Response::Insert(id, _) => id,
+            _ => panic!("insert failed"),
+        }
+    }
+
+    #[test]
+    fn restore_round_trips_self_referential_rows() {
+        let db = Database::new(schema());
+
+        let boss = insert(&db, 1, vec![Value::Text("boss".to_string()), Value::Foreign(0)]);
+        let report = insert(&db, 1, vec![Value::Text("report".to_string()), Value::Foreign(boss)]);
+
+        let bytes = db.snapshot();
+        let restored = Database::restore(&bytes, schema()).expect("restore should succeed");
+
+        match handle_request(Request { table_id: 1, txn_id: 0, command: Command::Get(boss) }, &restored) {
+            Response::Get(_, data) => assert_eq!(data, vec![Value::Text("boss".to_string()), Value::Foreign(0)]),
+            _ => panic!("get failed"),
+        }
+        match handle_request(Request { table_id: 1, txn_id: 0, command: Command::Get(report) }, &restored) {
+            Response::Get(_, data) => assert_eq!(data, vec![Value::Text("report".to_string()), Value::Foreign(boss)]),
+            _ => panic!("get failed"),
+        }
+
+        // The next insert must not collide with a restored row's id.
+        let new_id = insert(&restored, 1, vec![Value::Text("new hire".to_string()), Value::Foreign(boss)]);
+        assert!(new_id > report);
+    }
+}
+
+#[cfg(test)]
+mod join_tests {
+    use super::*;
+    use crate::schema::{ColumnMetadata, TableMetadata};
+
+    fn schema() -> Vec<TableMetadata> {
+        vec![
+            TableMetadata {
+                t_name: "authors".to_string(),
+                t_cols: vec![ColumnMetadata { c_name: "name".to_string(), c_type: Value::STRING, c_ref: 0 }],
+            },
+            TableMetadata {
+                t_name: "posts".to_string(),
+                t_cols: vec![ColumnMetadata { c_name: "author_id".to_string(), c_type: Value::FOREIGN, c_ref: 1 }],
+            },
+        ]
+    }
+
+    fn insert(db: &Database, table_id: i32, values: Vec<Value>) -> i64 {
+        match handle_request(Request { table_id, txn_id: 0, command: Command::Insert(values) }, db) {
+            Response::Insert(id, _) => id,
+            _ => panic!("insert failed"),
+        }
+    }
+
+    #[test]
+    fn join_eq_follows_a_single_referenced_row() {
+        let db = Database::new(schema());
+
+        let ada = insert(&db, 1, vec![Value::Text("ada".to_string())]);
+        let grace = insert(&db, 1, vec![Value::Text("grace".to_string())]);
+
+        let adas_post = insert(&db, 2, vec![Value::Foreign(ada)]);
+        insert(&db, 2, vec![Value::Foreign(grace)]);
+
+        let response = handle_request(Request {
+            table_id: 2,
+            txn_id: 0,
+            command: Command::Join(1, OP_EQ, Value::Integer(ada)),
+        }, &db);
+
+        match response {
+            Response::Query(ids) => assert_eq!(ids, vec![adas_post]),
+            _ => panic!("join failed"),
+        }
+    }
+
+    #[test]
+    fn join_ge_follows_every_referenced_row_at_or_above_the_bound() {
+        let db = Database::new(schema());
+
+        let ada = insert(&db, 1, vec![Value::Text("ada".to_string())]);
+        let grace = insert(&db, 1, vec![Value::Text("grace".to_string())]);
+
+        let adas_post = insert(&db, 2, vec![Value::Foreign(ada)]);
+        let graces_post = insert(&db, 2, vec![Value::Foreign(grace)]);
+
+        let response = handle_request(Request {
+            table_id: 2,
+            txn_id: 0,
+            command: Command::Join(1, OP_GE, Value::Integer(ada)),
+        }, &db);
+
+        match response {
+            Response::Query(mut ids) => {
+                ids.sort();
+                let mut expected = vec![adas_post, graces_post];
+                expected.sort();
+                assert_eq!(ids, expected);
+            },
+            _ => panic!("join failed"),
+        }
+    }
 }
\ No newline at end of file